@@ -0,0 +1,171 @@
+// Copyright (c) 2017-present PyO3 Project and Contributors
+//! Support for defining Python classes from Rust structs via `#[pyclass]`.
+
+use crate::type_object::{type_flags, PyTypeInfo};
+use crate::{ffi, AsPyPointer, PyErr, PyResult, Python};
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::ptr::NonNull;
+
+/// Trait implemented by `#[pyclass]` structs.
+///
+/// This is a thin marker over [`PyTypeInfo`] distinguishing user-defined classes (whose type
+/// object is built at runtime by [`initialize_type_object`]) from Python's native types (whose
+/// type object is a static CPython singleton).
+pub trait PyClass: PyTypeInfo<Type = Self> + Sized {}
+
+/// Build the `ffi::PyTypeObject` for `T` and allocate it on the heap as an instance of
+/// `metaclass`.
+///
+/// Unlike the static `ffi::PyTypeObject_INIT` template (which is exactly
+/// `sizeof::<ffi::PyTypeObject>()` bytes and is itself an instance of CPython's builtin `type`),
+/// the object returned here is allocated with `metaclass.tp_basicsize` bytes - mirroring how
+/// CPython's own `type_new`/`PyType_FromMetaclass` allocate `metatype->tp_basicsize` bytes
+/// rather than `sizeof(PyTypeObject)`. This is what makes a custom `#[pyclass(metaclass = ...)]`
+/// work at all: if the metaclass adds its own per-type fields on top of `type`'s layout, a type
+/// built from it needs room for them.
+pub fn initialize_type_object<T: PyClass>(
+    py: Python,
+    module_name: Option<&str>,
+    metaclass: &ffi::PyTypeObject,
+) -> PyResult<NonNull<ffi::PyTypeObject>> {
+    let type_object = unsafe {
+        let raw = ffi::PyType_GenericAlloc(metaclass as *const _ as *mut ffi::PyTypeObject, 0);
+        NonNull::new(raw as *mut ffi::PyTypeObject).ok_or_else(|| PyErr::fetch(py))?
+    };
+
+    unsafe {
+        let tp = type_object.as_ptr();
+        // `ob_type` (the new type's own type) is `metaclass`, not CPython's static `type`.
+        (*tp).ob_base.ob_base.ob_type = metaclass as *const _ as *mut ffi::PyTypeObject;
+        // `tp_name` is a NUL-terminated `char*` that must outlive the type object; since the
+        // type object itself lives for the rest of the process (it is stored in a `static
+        // LazyStaticType` and never freed), leaking this allocation is fine.
+        (*tp).tp_name = CString::new(T::NAME)
+            .expect("class name must not contain interior NUL bytes")
+            .into_raw();
+        (*tp).tp_basicsize = std::mem::size_of::<T>() as ffi::Py_ssize_t;
+        (*tp).tp_base =
+            <T::BaseType as PyTypeInfo>::type_object()? as *const _ as *mut ffi::PyTypeObject;
+        (*tp).tp_flags = tp_flags_for::<T>();
+
+        // Every type built here has `Py_TPFLAGS_HEAPTYPE` set, which means CPython's own
+        // `__name__`/`__qualname__`/`repr()` machinery reads `ht_name`/`ht_qualname` off the
+        // `PyHeapTypeObject` this allocation actually is, rather than `tp_name` alone.
+        // `PyType_GenericAlloc` zero-initializes the allocation, so these start out NULL; CPython's
+        // own `type_new` always sets them before calling `PyType_Ready`, and so do we.
+        let heap_type = tp as *mut ffi::PyHeapTypeObject;
+        let name = crate::types::PyString::new(py, T::NAME);
+        ffi::Py_INCREF(name.as_ptr());
+        (*heap_type).ht_name = name.as_ptr();
+        ffi::Py_INCREF(name.as_ptr());
+        (*heap_type).ht_qualname = name.as_ptr();
+
+        if ffi::PyType_Ready(tp) < 0 {
+            return Err(PyErr::fetch(py));
+        }
+
+        // No explicit `PyObject_GC_Track` here: `PyType_GenericAlloc` has already tracked the new
+        // type with the cyclic GC (every metaclass is a GC type), and tracking it twice is a
+        // fatal error in CPython.
+
+        if let Some(module_name) = module_name {
+            set_module(py, tp, module_name)?;
+        }
+    }
+
+    Ok(type_object)
+}
+
+/// Translate our `type_flags` bitset into the corresponding `ffi::Py_TPFLAGS_*` bits.
+fn tp_flags_for<T: PyTypeInfo>() -> std::os::raw::c_ulong {
+    let mut flags = ffi::Py_TPFLAGS_DEFAULT | ffi::Py_TPFLAGS_HEAPTYPE;
+
+    if T::FLAGS & type_flags::GC != 0 {
+        flags |= ffi::Py_TPFLAGS_HAVE_GC;
+    }
+    if T::FLAGS & type_flags::BASETYPE != 0 {
+        flags |= ffi::Py_TPFLAGS_BASETYPE;
+    }
+    if T::FLAGS & type_flags::FINAL != 0 {
+        // `Py_TPFLAGS_IMMUTABLETYPE` is CPython's own "this type cannot be subclassed and its
+        // attributes cannot be reassigned from Python" flag - exactly what `FINAL` asks for.
+        // `type_object::LazyStaticType::get_or_init` already rejects `FINAL | BASETYPE` at
+        // compile time, so `BASETYPE` is never set here alongside it.
+        flags |= ffi::Py_TPFLAGS_IMMUTABLETYPE;
+    }
+
+    flags
+}
+
+unsafe fn set_module(py: Python, tp: *mut ffi::PyTypeObject, module_name: &str) -> PyResult<()> {
+    let module = crate::types::PyString::new(py, module_name);
+    if ffi::PyDict_SetItemString(
+        (*tp).tp_dict,
+        "__module__\0".as_ptr() as *const c_char,
+        module.as_ptr(),
+    ) < 0
+    {
+        return Err(PyErr::fetch(py));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PyType;
+    use crate::{exceptions, PyResult};
+
+    struct Final;
+
+    unsafe impl PyTypeInfo for Final {
+        type Type = Final;
+        type BaseType = exceptions::Exception;
+        type MetaType = PyType;
+        type ConcreteLayout = <exceptions::Exception as PyTypeInfo>::ConcreteLayout;
+        type Initializer = <exceptions::Exception as PyTypeInfo>::Initializer;
+
+        const NAME: &'static str = "Final";
+        const MODULE: Option<&'static str> = None;
+        const FLAGS: usize = type_flags::FINAL;
+
+        fn type_object() -> PyResult<&'static ffi::PyTypeObject> {
+            unreachable!("tp_flags_for never calls type_object()")
+        }
+    }
+
+    impl PyClass for Final {}
+
+    #[test]
+    fn tp_flags_for_always_sets_default_and_heaptype() {
+        let flags = tp_flags_for::<exceptions::Exception>();
+        assert_ne!(flags & ffi::Py_TPFLAGS_DEFAULT, 0);
+        assert_ne!(flags & ffi::Py_TPFLAGS_HEAPTYPE, 0);
+    }
+
+    #[test]
+    fn tp_flags_for_translates_basetype() {
+        let flags = tp_flags_for::<exceptions::Exception>();
+        assert_ne!(
+            flags & ffi::Py_TPFLAGS_BASETYPE,
+            0,
+            "Exception declares type_flags::BASETYPE"
+        );
+    }
+
+    #[test]
+    fn tp_flags_for_translates_final_to_immutabletype_and_not_basetype() {
+        let flags = tp_flags_for::<Final>();
+        assert_ne!(
+            flags & ffi::Py_TPFLAGS_IMMUTABLETYPE,
+            0,
+            "type_flags::FINAL must translate to Py_TPFLAGS_IMMUTABLETYPE"
+        );
+        assert_eq!(
+            flags & ffi::Py_TPFLAGS_BASETYPE,
+            0,
+            "a FINAL type must never also get Py_TPFLAGS_BASETYPE"
+        );
+    }
+}
@@ -4,11 +4,12 @@
 use crate::instance::Py;
 use crate::pyclass::{initialize_type_object, PyClass};
 use crate::pyclass_init::PyObjectInit;
-use crate::types::{PyAny, PyType};
-use crate::{ffi, AsPyPointer, Python};
+use crate::types::{PyAny, PyDict, PyTuple, PyType};
+use crate::{exceptions, ffi, AsPyPointer, PyErr, PyResult, Python};
 use std::cell::UnsafeCell;
 use std::ptr::NonNull;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
 
 /// `T: PyObjectLayout<U>` represents that `T` is a concrete representaion of `U` in Python heap.
 /// E.g., `PyClassShell` is a concrete representaion of all `pyclass`es, and `ffi::PyObject`
@@ -59,6 +60,10 @@ pub mod type_flags {
 
     /// The class declared by #[pyclass(extends=~)]
     pub const EXTENDED: usize = 1 << 4;
+
+    /// The class cannot be subclassed and its attributes cannot be reassigned from Python,
+    /// i.e. it maps to CPython's `Py_TPFLAGS_IMMUTABLETYPE` (and implies `BASETYPE` is unset).
+    pub const FINAL: usize = 1 << 5;
 }
 
 /// Python type information.
@@ -86,6 +91,14 @@ pub unsafe trait PyTypeInfo: Sized {
     /// Base class
     type BaseType: PyTypeInfo + PyTypeObject;
 
+    /// Metaclass, i.e. the `ob_type` of this type's own type object.
+    ///
+    /// Most classes are instances of Python's builtin `type` and can leave this as `PyType`.
+    /// `#[pyclass(metaclass = MyMeta)]` sets this to a custom `PyTypeInfo` so that
+    /// `initialize_type_object` builds the type object with `MyMeta` as its metaclass instead of
+    /// the static `type` template, e.g. to integrate with an `ABCMeta`-based Python API.
+    type MetaType: PyTypeInfo + PyTypeObject;
+
     /// Layout
     type ConcreteLayout: PyObjectLayout<Self>;
 
@@ -93,18 +106,22 @@ pub unsafe trait PyTypeInfo: Sized {
     type Initializer: PyObjectInit<Self>;
 
     /// PyTypeObject instance for this type.
-    fn type_object() -> &'static ffi::PyTypeObject;
+    fn type_object() -> PyResult<&'static ffi::PyTypeObject>;
 
     /// Check if `*mut ffi::PyObject` is instance of this type
+    ///
+    /// Panics if the underlying type object could not be initialized.
     fn is_instance(object: &PyAny) -> bool {
-        unsafe {
-            ffi::PyObject_TypeCheck(object.as_ptr(), Self::type_object() as *const _ as _) != 0
-        }
+        let type_object = Self::type_object().expect("type object initialization failed");
+        unsafe { ffi::PyObject_TypeCheck(object.as_ptr(), type_object as *const _ as _) != 0 }
     }
 
     /// Check if `*mut ffi::PyObject` is exact instance of this type
+    ///
+    /// Panics if the underlying type object could not be initialized.
     fn is_exact_instance(object: &PyAny) -> bool {
-        unsafe { (*object.as_ptr()).ob_type == Self::type_object() as *const _ as _ }
+        let type_object = Self::type_object().expect("type object initialization failed");
+        unsafe { (*object.as_ptr()).ob_type == type_object as *const _ as _ }
     }
 }
 
@@ -124,22 +141,134 @@ where
     T: PyTypeInfo,
 {
     fn type_object() -> Py<PyType> {
-        unsafe { Py::from_borrowed_ptr(<Self as PyTypeInfo>::type_object() as *const _ as _) }
+        let type_object = <Self as PyTypeInfo>::type_object().unwrap_or_else(|e| {
+            let gil = Python::acquire_gil();
+            e.print(gil.python());
+            panic!("An error occurred while initializing class {}", Self::NAME)
+        });
+        unsafe { Py::from_borrowed_ptr(type_object as *const _ as _) }
+    }
+}
+
+/// Dynamically create and register a new Python subclass of the `#[pyclass]` type `T`.
+///
+/// This is the runtime counterpart to the compile-time `#[pyclass(extends = T)]`: it lets code
+/// that doesn't know the subclass shape ahead of time (plugin systems, ORM-style models, ...)
+/// synthesize a Python class derived from `T` on the fly, equivalent to calling Python's builtin
+/// `type(name, bases, dict)` with `T`'s own type as a base.
+///
+/// `T`'s type object is always the new class' first base - it isn't caller-supplied, so the
+/// `type_flags::BASETYPE` check below is actually checking the class being subclassed, rather
+/// than something `bases` merely happened to contain. `extra_bases` appends further bases after
+/// it, for `class Sub(T, *extra_bases): ...`-style multiple inheritance.
+///
+/// Returns an error if `T` does not have `type_flags::BASETYPE` set (i.e. `T` forbids being
+/// subclassed), or if creating the new type object fails.
+pub fn new_subclass<T>(
+    py: Python,
+    name: &str,
+    extra_bases: &[&PyAny],
+    dict: &PyDict,
+) -> PyResult<Py<PyType>>
+where
+    T: PyTypeInfo + PyTypeObject,
+{
+    if T::FLAGS & type_flags::BASETYPE == 0 {
+        return Err(PyErr::new::<exceptions::TypeError, _>(format!(
+            "type '{}' is not an acceptable base type",
+            T::NAME
+        )));
     }
+
+    let base = <T as PyTypeObject>::type_object();
+    let base: &PyAny = base.as_ref(py);
+    let mut bases: Vec<&PyAny> = Vec::with_capacity(1 + extra_bases.len());
+    bases.push(base);
+    bases.extend_from_slice(extra_bases);
+    let bases = PyTuple::new(py, &bases);
+
+    // The new heap type is built by calling `T`'s own metaclass with `(name, bases, dict)`,
+    // exactly as CPython does internally for a `class Sub(Base): ...` statement.
+    let metaclass = base.get_type();
+    let new_type = metaclass.call1((name, bases, dict))?;
+    Ok(unsafe { Py::from_borrowed_ptr(new_type.as_ptr()) })
+}
+
+/// The three states a lazily-initialized type object can be in.
+///
+/// `UNINIT` -> `INITIALIZING` -> `DONE` is the only possible transition when `init` succeeds.
+/// Once `DONE` has been observed (with `Acquire`), the value is guaranteed to be fully
+/// initialized and readable without any further synchronization. If `init` fails, the state
+/// drops back to `UNINIT` so a later call can retry (see `lazy_init`).
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const DONE: u8 = 2;
+
+/// Drive `state` through `UNINIT` -> `INITIALIZING` -> `DONE`, running `init` to completion
+/// exactly once.
+///
+/// `init` is expected to publish its result itself (e.g. by writing into an `UnsafeCell` it
+/// closes over) and only use this return value to signal success/failure; on success this
+/// returns `Ok(())` once `init` has definitely run (either in this call or a previous one).
+///
+/// If `init` fails, `state` resets to `UNINIT` so a later call can retry from scratch; any
+/// threads currently waiting in `INITIALIZING` observe the reset and race to become the new
+/// initializer themselves, rather than waiting forever for a `DONE` that will never arrive.
+fn lazy_init<E>(state: &AtomicU8, init: impl FnOnce() -> Result<(), E>) -> Result<(), E> {
+    loop {
+        match state.load(Ordering::Acquire) {
+            DONE => return Ok(()),
+            UNINIT => {
+                if state
+                    .compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Acquire)
+                    .is_err()
+                {
+                    // Lost the race to another thread; go around and observe its result.
+                    continue;
+                }
+                return match init() {
+                    Ok(()) => {
+                        // `Release` guarantees other threads' `Acquire` load above sees
+                        // everything `init` wrote before we flip the state to `DONE`.
+                        state.store(DONE, Ordering::Release);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        state.store(UNINIT, Ordering::Release);
+                        Err(e)
+                    }
+                };
+            }
+            _ /* INITIALIZING */ => release_gil_and_yield(),
+        }
+    }
+}
+
+/// Release the GIL (if this thread holds it) and yield once, to give the thread currently
+/// running `init` - which needs the GIL to call into CPython - a chance to make progress.
+///
+/// A bare spin loop here would be wrong: if this thread already holds the GIL (e.g. it is
+/// nested inside some other Python callback) at the moment it loses the race to initialize,
+/// spinning without releasing the GIL would starve the initializing thread of the GIL forever,
+/// deadlocking both. `Python::allow_threads` releases the real GIL for the closure's duration
+/// even when called reentrantly, so the initializing thread is always able to proceed.
+fn release_gil_and_yield() {
+    let gil = Python::acquire_gil();
+    gil.python().allow_threads(thread::yield_now);
 }
 
 /// Lazy type object for Exceptions
 #[doc(hidden)]
 pub struct LazyHeapType {
     value: UnsafeCell<Option<NonNull<ffi::PyTypeObject>>>,
-    initialized: AtomicBool,
+    state: AtomicU8,
 }
 
 impl LazyHeapType {
     pub const fn new() -> Self {
         LazyHeapType {
             value: UnsafeCell::new(None),
-            initialized: AtomicBool::new(false),
+            state: AtomicU8::new(UNINIT),
         }
     }
 
@@ -147,16 +276,15 @@ impl LazyHeapType {
     where
         F: Fn(Python) -> NonNull<ffi::PyTypeObject>,
     {
-        if !self
-            .initialized
-            .compare_and_swap(false, true, Ordering::Acquire)
-        {
+        let result: Result<(), std::convert::Infallible> = lazy_init(&self.state, || {
             // We have to get the GIL before setting the value to the global!!!
             let gil = Python::acquire_gil();
             unsafe {
                 *self.value.get() = Some(constructor(gil.python()));
             }
-        }
+            Ok(())
+        });
+        result.unwrap();
         unsafe { (*self.value.get()).unwrap() }
     }
 }
@@ -167,37 +295,153 @@ impl LazyHeapType {
 // to allow sharing on the Rust side too.
 unsafe impl Sync for LazyHeapType {}
 
+/// Compile-time check that `T` does not declare an incoherent combination of `FINAL`: a class
+/// can't be both `FINAL` (unsubclassable) and `BASETYPE` (subclassable), and a class can't
+/// subclass a base that is itself `FINAL`.
+///
+/// Both sides are fully determined by `T::FLAGS`/`T::BaseType::FLAGS`, so this is checked once
+/// per monomorphization of `LazyStaticType::get_or_init::<T>` using the classic pre-`const {
+/// panic!() }` static-assertion trick (an associated const that underflows, which is a hard error
+/// in const evaluation), rather than a `debug_assert!` that would only fire the first time a given
+/// `T` happens to be lazily touched, and not at all in release builds.
+struct FinalFlagsAreCoherent<T>(std::marker::PhantomData<T>);
+
+impl<T: PyClass> FinalFlagsAreCoherent<T> {
+    const CHECK: usize = 0 - ((T::FLAGS & type_flags::FINAL != 0
+        && T::FLAGS & type_flags::BASETYPE != 0)
+        || (T::BaseType::FLAGS & type_flags::FINAL != 0)) as usize;
+}
+
 /// Lazy type object for PyClass
 #[doc(hidden)]
 pub struct LazyStaticType {
-    value: UnsafeCell<ffi::PyTypeObject>,
-    initialized: AtomicBool,
+    // Heap-allocated, rather than a statically embedded `ffi::PyTypeObject`, so that a custom
+    // `T::MetaType` whose instances (i.e. type objects) need more storage than CPython's builtin
+    // `type` is properly sized - see `initialize_type_object`. A static `UnsafeCell<PyTypeObject>`
+    // can only ever be `sizeof::<ffi::PyTypeObject>()` bytes, which isn't enough room for a
+    // metaclass that tacks its own fields onto the end of `type`'s layout.
+    value: UnsafeCell<Option<NonNull<ffi::PyTypeObject>>>,
+    state: AtomicU8,
 }
 
 impl LazyStaticType {
     pub const fn new() -> Self {
         LazyStaticType {
-            value: UnsafeCell::new(ffi::PyTypeObject_INIT),
-            initialized: AtomicBool::new(false),
+            value: UnsafeCell::new(None),
+            state: AtomicU8::new(UNINIT),
         }
     }
 
-    pub fn get_or_init<T: PyClass>(&self) -> &ffi::PyTypeObject {
-        if !self
-            .initialized
-            .compare_and_swap(false, true, Ordering::Acquire)
-        {
+    /// Returns the initialized type object, running `initialize_type_object` until it succeeds
+    /// once; a failed attempt returns its error and is retried on the next call.
+    ///
+    /// The hot, steady-state path (after the type has been initialized once) is a single
+    /// `Acquire` load and no locking.
+    pub fn get_or_init<T: PyClass>(&self) -> PyResult<&ffi::PyTypeObject> {
+        // `FINAL` forbids subclassing, so it's incoherent for a class to be both `FINAL` and
+        // `BASETYPE`, and incoherent to subclass a base that is itself `FINAL`. Both sides are
+        // known purely from `T::FLAGS`/`T::BaseType::FLAGS`, so check them at compile time
+        // (per-monomorphization) rather than as a `debug_assert!` that only fires the first time
+        // a given `T` happens to be lazily touched, and not at all in release builds.
+        let _ = FinalFlagsAreCoherent::<T>::CHECK;
+
+        lazy_init(&self.state, || {
             let gil = Python::acquire_gil();
             let py = gil.python();
-            initialize_type_object::<T>(py, T::MODULE, unsafe { &mut *self.value.get() })
-                .unwrap_or_else(|e| {
-                    e.print(py);
-                    panic!("An error occurred while initializing class {}", T::NAME)
-                });
-        }
-        unsafe { &*self.value.get() }
+            // The metaclass' own type object must already be initialized so that
+            // `initialize_type_object` can use it as `ob_type` for the type being built.
+            let metaclass = <T::MetaType as PyTypeInfo>::type_object()?;
+            let type_object = initialize_type_object::<T>(py, T::MODULE, metaclass)?;
+            unsafe { *self.value.get() = Some(type_object) };
+            Ok(())
+        })?;
+        Ok(unsafe { (*self.value.get()).unwrap().as_ref() })
     }
 }
 
 // This is necessary for making static `LazyStaticType`s
 unsafe impl Sync for LazyStaticType {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn lazy_heap_type_runs_constructor_exactly_once_under_contention() {
+        static mut DUMMY: ffi::PyTypeObject = ffi::PyTypeObject_INIT;
+
+        static LAZY: LazyHeapType = LazyHeapType::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let calls = Arc::clone(&calls);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    // Make every thread race to be first, rather than trickling in.
+                    barrier.wait();
+                    LAZY.get_or_init(|_py| {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        unsafe { NonNull::new_unchecked(std::ptr::addr_of_mut!(DUMMY)) }
+                    });
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn new_subclass_creates_a_runtime_subclass_of_a_basetype() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let dict = PyDict::new(py);
+
+        let subclass = new_subclass::<exceptions::Exception>(py, "MySubclass", &[], dict)
+            .expect("Exception has type_flags::BASETYPE set, so subclassing it must succeed");
+        let subclass = subclass.as_ref(py);
+
+        assert!(subclass.is_subclass::<exceptions::Exception>().unwrap());
+        assert!(subclass
+            .call1(())
+            .unwrap()
+            .is_instance::<exceptions::Exception>()
+            .unwrap());
+    }
+
+    #[test]
+    fn new_subclass_rejects_a_base_without_basetype() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let dict = PyDict::new(py);
+
+        let err = new_subclass::<crate::types::PyBool>(py, "MySubclass", &[], dict)
+            .expect_err("bool does not have type_flags::BASETYPE set");
+        assert!(err.is_instance::<exceptions::TypeError>(py));
+    }
+
+    #[test]
+    fn lazy_init_resets_to_uninit_and_retries_after_a_failed_attempt() {
+        let state = AtomicU8::new(UNINIT);
+
+        let first: Result<(), &str> = lazy_init(&state, || Err("boom"));
+        assert_eq!(first, Err("boom"));
+        assert_eq!(
+            state.load(Ordering::SeqCst),
+            UNINIT,
+            "a failed init must not get stuck as INITIALIZING forever"
+        );
+
+        let second: Result<(), &str> = lazy_init(&state, || Ok(()));
+        assert_eq!(second, Ok(()));
+        assert_eq!(state.load(Ordering::SeqCst), DONE);
+    }
+}